@@ -1,25 +1,91 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::{create_dir_all, copy};
+use std::os::unix::fs::MetadataExt;
 use std::process::Command;
 use std::{fs::File, path::PathBuf};
 use std::path::Path;
 use std::fmt;
-use std::sync::Mutex;
+use std::io::Read;
+use std::sync::{Condvar, Mutex, OnceLock};
 
 use clap::Parser;
 use exif::{Tag, In, Value};
 use chrono::{Utc, DateTime, Datelike, NaiveDateTime};
+use image::imageops::FilterType;
 use indicatif::ParallelProgressIterator;
+use pathdiff::diff_paths;
 use rayon::prelude::*;
 use serde::Deserialize;
+use walkdir::WalkDir;
+
+/// Size of the chunks read from disk while hashing a file, so we never
+/// have to load a whole picture in memory just to compare it to another.
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum number of pictures decoded concurrently while generating
+/// gallery thumbnails, so a batch of large images can't exhaust memory.
+const THUMBNAIL_CONCURRENCY: usize = 4;
+
+/// File extensions picobak recognizes as media files when walking a
+/// directory recursively.
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "bmp", "webp", "tif", "tiff", "heic", "heif",
+    "cr2", "nef", "arw", "dng", "raf", "orf",
+    "mov", "mp4", "m4v", "avi", "3gp"
+];
+
+/// Extensions `validate_decodable` actually probes. RAW, HEIC and video
+/// formats aren't here because the `image` crate can't decode them
+/// anyway, so there's nothing to validate beyond the filesystem checks
+/// already performed before this runs.
+const DECODABLE_IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "webp", "tif", "tiff"];
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
     /// Pictures library directory
     backup_root: String,
-    /// Picture to backup. Alternatively, you can send a list of
-    /// pictures to backup via stdin.
+    /// Picture to backup, or a directory to walk recursively for media
+    /// files. Alternatively, you can send a list of pictures to backup
+    /// via stdin.
     file_path: Option<String>,
+    /// How pictures are organized under backup_root.
+    #[arg(long, value_enum, default_value = "date")]
+    layout: Layout,
+    /// After a successful copy whose capture date wasn't read from
+    /// embedded EXIF, stamp the derived date into the backed-up copy
+    /// via exiftool. The source file is never touched. Ignored with
+    /// `--layout content-addressed`.
+    #[arg(long)]
+    write_dates: bool,
+    /// When file_path is a directory, don't descend into a mount point
+    /// with a different device id than the starting directory.
+    #[arg(short = 'x', long = "one-file-system")]
+    one_file_system: bool,
+    /// Decode each newly-copied picture and write a downscaled
+    /// thumbnail plus a static per-day HTML gallery index, so the
+    /// library can be browsed offline right after the backup.
+    #[arg(long)]
+    gallery: bool,
+    /// Longest edge, in pixels, of generated gallery thumbnails.
+    #[arg(long, default_value_t = 1600)]
+    thumb_size: u32,
+    /// Remove the source after its content has been verified to match
+    /// the backed-up copy, turning picobak into an ingest tool that
+    /// drains a SD card or staging folder rather than duplicating it.
+    #[arg(long = "move")]
+    move_files: bool,
+}
+
+/// Storage layout used to lay pictures out under `backup_root`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Layout {
+    /// backup_root/YYYY/MM/DD/<original-name>
+    Date,
+    /// backup_root/content/<first-2-hex>/<hash><ext>, with a
+    /// backup_root/date/YYYY/MM/DD/<original-name> symlink back to it.
+    ContentAddressed,
 }
 
 /// Structure used to parse the JSON output of the exiftool program.
@@ -31,13 +97,14 @@ struct ExifToolEntry {
 
 enum BackupSuccess {
     AlreadyBackup(String),
-    Backup(String, PictureDatetimeOrigin)
+    Backup(PathBuf, PictureDatetimeOrigin)
 }
 
 enum BackupFailure {
     AlreadyBackupButDifferent(String),
     CopyError(String),
-    IncorrectFilename(String)
+    IncorrectFilename(String),
+    Corrupt(String)
 }
 
 enum PictureDatetimeOrigin {
@@ -48,12 +115,66 @@ enum PictureDatetimeOrigin {
 
 static CREATE_DIR_MUTEX: Mutex<()> = Mutex::new(());
 
+/// One entry in a day's gallery index: an original picture linked to
+/// its downscaled thumbnail, sorted by capture time.
+struct GalleryEntry {
+    original: PathBuf,
+    thumbnail: PathBuf,
+    datetime: DateTime<Utc>
+}
+
+static GALLERY_INDEX: OnceLock<Mutex<HashMap<(i32, u32, u32), Vec<GalleryEntry>>>> = OnceLock::new();
+
+fn gallery_index() -> &'static Mutex<HashMap<(i32, u32, u32), Vec<GalleryEntry>>> {
+    GALLERY_INDEX.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+static THUMBNAIL_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+fn thumbnail_semaphore() -> &'static Semaphore {
+    THUMBNAIL_SEMAPHORE.get_or_init(|| Semaphore::new(THUMBNAIL_CONCURRENCY))
+}
+
+/// A counting semaphore bounding how many permits (here, concurrent
+/// image decodes) can be held at once.
+struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar
+}
+
+struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore { permits: Mutex::new(permits), available: Condvar::new() }
+    }
+
+    fn acquire(&self) -> SemaphorePermit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit { semaphore: self }
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.available.notify_one();
+    }
+}
+
 impl fmt::Display for BackupFailure {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::AlreadyBackupButDifferent(s) => write!(f, "{}: already exists in the photo library but has a different content", s),
             Self::CopyError(s) => write!(f, "Copy error, {}", s),
-            Self::IncorrectFilename(s) => write!(f, "Incorrect Filename, {}", s)
+            Self::IncorrectFilename(s) => write!(f, "Incorrect Filename, {}", s),
+            Self::Corrupt(s) => write!(f, "Corrupt or unreadable file, {}", s)
         }
     }
 }
@@ -62,13 +183,7 @@ fn main() {
     let cli = CliArgs::parse();
 
     validate_args(&cli);
-    let stdin = std::io::stdin();
-    let filepaths = match cli.file_path {
-        Some(ref fp) => vec!(Ok(fp.to_string())),
-        None => stdin.lines()
-            .map(|l| l.map_err(|_|BackupFailure::IncorrectFilename(String::from("Can't parse filename from stdin"))))
-            .collect()
-    };
+    let filepaths = collect_file_paths(&cli);
 
     let filepaths_len = filepaths.len() as u64;
     let res: Vec<Result<BackupSuccess, BackupFailure>> = filepaths
@@ -80,9 +195,59 @@ fn main() {
         })
         .collect();
 
+    if cli.gallery {
+        write_gallery_indices();
+    }
+
     display_backup_result(res)
 }
 
+/// Build the list of file paths to backup: a single picture, a
+/// directory walked recursively for media files, or one path per line
+/// read from stdin.
+fn collect_file_paths(cli: &CliArgs) -> Vec<Result<String, BackupFailure>> {
+    match &cli.file_path {
+        Some(fp) if Path::new(fp).is_dir() => collect_directory_file_paths(Path::new(fp), cli.one_file_system),
+        Some(fp) => vec![Ok(fp.to_string())],
+        None => {
+            let stdin = std::io::stdin();
+            stdin.lines()
+                .map(|l| l.map_err(|_| BackupFailure::IncorrectFilename(String::from("Can't parse filename from stdin"))))
+                .collect()
+        }
+    }
+}
+
+/// Recursively walk `root`, collecting media files by extension. When
+/// `one_file_system` is set, directories whose device id differs from
+/// `root`'s are pruned, so crossing into a mounted external drive or
+/// network share doesn't silently pull in unintended files.
+fn collect_directory_file_paths(root: &Path, one_file_system: bool) -> Vec<Result<String, BackupFailure>> {
+    let root_dev = root.metadata().ok().map(|m| m.dev());
+    WalkDir::new(root)
+        .into_iter()
+        .filter_entry(move |entry| {
+            if !one_file_system {
+                return true;
+            }
+            match (root_dev, entry.metadata().ok().map(|m| m.dev())) {
+                (Some(root_dev), Some(entry_dev)) => root_dev == entry_dev,
+                _ => true
+            }
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && is_media_file(entry.path()))
+        .map(|entry| Ok(entry.path().to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Whether `path` has an extension picobak recognizes as a media file.
+fn is_media_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
 fn display_backup_result(results: Vec<Result<BackupSuccess, BackupFailure>>) {
     let mut nb_copy_exif: u32 = 0;
     let mut nb_copy_exiftool: u32 = 0;
@@ -123,55 +288,357 @@ fn backup_file(cli: &CliArgs, file_path: &str) -> Result<BackupSuccess, BackupFa
     let file = File::open(filename).map_err(
         |e| BackupFailure::CopyError(format!("cannot open the {} file: {}", file_path, e))
     )?;
-    let (datetime, origin) = get_picture_datetime(file_path, &file);
-
-    let picture_dir = find_backup_dir(&cli.backup_root, &datetime);
-    upsert_picture_directory(&picture_dir);
+    validate_decodable(file_path).map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?;
+    let (datetime, origin) = get_picture_datetime(file_path, &file)
+        .map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?;
 
     let filename_name = filename.file_name()
         .ok_or_else(|| BackupFailure::IncorrectFilename(
             format!("Incorrect file name {}", filename.display())))?;
+
+    let result = match cli.layout {
+        Layout::Date => backup_file_date_layout(cli, filename, filename_name, file_path, &datetime, origin),
+        Layout::ContentAddressed => backup_file_content_addressed_layout(cli, filename, filename_name, file_path, &datetime, origin)
+    };
+
+    if cli.write_dates && cli.layout == Layout::Date {
+        if let Ok(BackupSuccess::Backup(ref target, ref origin)) = result {
+            if !matches!(origin, PictureDatetimeOrigin::Exif) {
+                write_picture_dates(target, &datetime);
+            }
+        }
+    }
+
+    if cli.gallery {
+        if let Ok(BackupSuccess::Backup(ref target, _)) = result {
+            generate_gallery_thumbnail(cli, target, &datetime);
+        }
+    }
+
+    result
+}
+
+/// Decode a newly backed-up picture, write its thumbnail under
+/// backup_root/.thumbs/YYYY/MM/DD/ and record it in that day's gallery
+/// index. Best effort: a failure here is reported but doesn't fail the
+/// backup.
+fn generate_gallery_thumbnail(cli: &CliArgs, target: &Path, datetime: &DateTime<Utc>) {
+    let thumb_dir = find_backup_dir(&Path::new(&cli.backup_root).join(".thumbs"), datetime);
+    if let Err(e) = upsert_picture_directory(&thumb_dir) {
+        eprintln!("WARNING: cannot create thumbnail dir {}: {}", thumb_dir.display(), e);
+        return;
+    }
+
+    let thumb_target = match target.file_name() {
+        Some(name) => thumb_dir.join(name),
+        None => {
+            eprintln!("WARNING: cannot derive a thumbnail name for {}", target.display());
+            return;
+        }
+    };
+
+    match generate_thumbnail(target, &thumb_target, cli.thumb_size) {
+        Ok(_) => record_gallery_entry(datetime, target.to_path_buf(), thumb_target),
+        Err(e) => eprintln!("WARNING: cannot generate thumbnail for {}: {}", target.display(), e)
+    }
+}
+
+/// Decode `source` and write a downscaled thumbnail to `target`, whose
+/// longest edge is `thumb_size` pixels, preserving aspect ratio via
+/// Lanczos3 resampling. Bounded by a semaphore so decoding many large
+/// pictures concurrently doesn't exhaust memory. Decoding untrusted
+/// images can panic inside C-backed libraries, so the decode itself is
+/// caught here and turned into an ordinary `Err`. This runs on the
+/// backed-up copy after the backup itself (including `--move`'s source
+/// removal) has completed.
+fn generate_thumbnail(source: &Path, target: &Path, thumb_size: u32) -> Result<(), String> {
+    let _permit = thumbnail_semaphore().acquire();
+    let source = source.to_path_buf();
+    let target = target.to_path_buf();
+    let source_display = source.display().to_string();
+    std::panic::catch_unwind(move || {
+        let picture = image::open(&source).map_err(|e| format!("cannot decode {}: {}", source.display(), e))?;
+        picture.resize(thumb_size, thumb_size, FilterType::Lanczos3)
+            .save(&target)
+            .map_err(|e| format!("cannot write thumbnail {}: {}", target.display(), e))
+    }).unwrap_or_else(|_| Err(format!("panicked while decoding {}", source_display)))
+}
+
+/// Record a picture/thumbnail pair in its day's in-memory gallery
+/// index, to be flushed to a static HTML index once the backup run
+/// completes.
+fn record_gallery_entry(datetime: &DateTime<Utc>, original: PathBuf, thumbnail: PathBuf) {
+    let day = (datetime.year(), datetime.month(), datetime.day());
+    gallery_index().lock().unwrap()
+        .entry(day)
+        .or_default()
+        .push(GalleryEntry { original, thumbnail, datetime: *datetime });
+}
+
+/// Write a static backup_root/.thumbs/YYYY/MM/DD/index.html for every
+/// day that had at least one picture processed this run, linking each
+/// original to its thumbnail and sorted by capture time.
+fn write_gallery_indices() {
+    let mut index = gallery_index().lock().unwrap();
+    for (day, entries) in index.iter_mut() {
+        entries.sort_by_key(|entry| entry.datetime);
+        let day_dir = entries.first().and_then(|e| e.thumbnail.parent()).map(PathBuf::from);
+        let Some(day_dir) = day_dir else { continue };
+        let index_path = day_dir.join("index.html");
+        if let Err(e) = std::fs::write(&index_path, render_gallery_index_html(*day, entries, &day_dir)) {
+            eprintln!("WARNING: cannot write gallery index {}: {}", index_path.display(), e);
+        }
+    }
+}
+
+/// Escape the characters that would otherwise let an interpolated
+/// string break out of an HTML attribute or tag, so a filename
+/// containing `"`, `<`, `&` etc. (not unusual from phone exports or
+/// cloud-sync renames) can't inject markup into a generated page.
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Render a day's gallery index as a minimal, dependency-free static
+/// HTML page. `href`/`src` are resolved relative to `day_dir` (where the
+/// index.html itself lives), since browsers resolve a file:// page's
+/// relative links against the page's own directory, not the cwd.
+fn render_gallery_index_html(day: (i32, u32, u32), entries: &[GalleryEntry], day_dir: &Path) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>picobak gallery - {:04}-{:02}-{:02}</title></head><body>\n",
+        day.0, day.1, day.2
+    );
+    for entry in entries {
+        let original = relative_to(&entry.original, day_dir);
+        let thumbnail = relative_to(&entry.thumbnail, day_dir);
+        html.push_str(&format!(
+            "<a href=\"{}\"><img src=\"{}\" loading=\"lazy\" alt=\"{}\"></a>\n",
+            html_escape(&original.to_string_lossy()),
+            html_escape(&thumbnail.to_string_lossy()),
+            html_escape(&entry.datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+        ));
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// If `--move` is set and the backed-up copy's content has been
+/// verified to match the source, remove the source. Never removes a
+/// file whose copy wasn't verified, so an interrupted or truncated
+/// copy never destroys the only surviving original.
+fn maybe_remove_source(cli: &CliArgs, file_path: &str, verified: bool) {
+    if !cli.move_files {
+        return;
+    }
+    if !verified {
+        eprintln!("WARNING: --move: refusing to remove source {}, could not verify the copy", file_path);
+        return;
+    }
+    if let Err(e) = std::fs::remove_file(file_path) {
+        eprintln!("WARNING: --move: could not remove source {}: {}", file_path, e);
+    }
+}
+
+/// Stamp the derived capture date (with the time set to 00:00:00) into
+/// a backed-up copy via exiftool, so the directory position and the embedded metadata stay
+/// consistent for files (or formats, like MOV/MP4) that had no usable
+/// embedded EXIF to begin with. Never touches the source file. Best
+/// effort: a failure here is reported but doesn't fail the backup.
+fn write_picture_dates(target: &Path, datetime: &DateTime<Utc>) {
+    let all_dates = format!("-AllDates={} 00:00:00", datetime.format("%Y:%m:%d"));
+    let output = Command::new("exiftool")
+        .args([&all_dates, "-overwrite_original", &target.to_string_lossy()])
+        .output();
+    match output {
+        Ok(o) if o.status.success() => (),
+        Ok(o) => eprintln!(
+            "WARNING: exiftool could not write dates into {}: {}",
+            target.display(), String::from_utf8_lossy(&o.stderr)
+        ),
+        Err(e) => eprintln!("WARNING: cannot run exiftool to write dates into {}: {}", target.display(), e)
+    }
+}
+
+/// Backup a file under the plain backup_root/YYYY/MM/DD tree.
+fn backup_file_date_layout(
+    cli: &CliArgs,
+    filename: &Path,
+    filename_name: &OsStr,
+    file_path: &str,
+    datetime: &DateTime<Utc>,
+    origin: PictureDatetimeOrigin
+) -> Result<BackupSuccess, BackupFailure> {
+    let picture_dir = find_backup_dir(Path::new(&cli.backup_root), datetime);
+    upsert_picture_directory(&picture_dir)
+        .map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?;
+
     let target_filename = picture_dir.join(filename_name);
     if !target_filename.is_file() {
         match copy(filename, &target_filename) {
-            Ok(_) => Ok(BackupSuccess::Backup(
-                target_filename.into_os_string().into_string().unwrap(),
-                origin)),
+            Ok(_) => {
+                let verified = same_files(filename, &target_filename).unwrap_or(false);
+                maybe_remove_source(cli, file_path, verified);
+                Ok(BackupSuccess::Backup(target_filename, origin))
+            },
             Err(_) => Err(BackupFailure::CopyError(String::from(file_path)))
 
         }
-    } else if same_files(filename, &target_filename) {
+    } else if same_files(filename, &target_filename).map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))? {
+        maybe_remove_source(cli, file_path, true);
         Ok(BackupSuccess::AlreadyBackup(String::from(file_path)))
     } else {
         Err(BackupFailure::AlreadyBackupButDifferent(format!("{} => {}", file_path, target_filename.display())))
     }
 }
 
-fn upsert_picture_directory(picture_dir: &PathBuf) {
+/// Backup a file under backup_root/content/<hash-prefix>/<hash><ext>,
+/// deduplicated by content across the whole library regardless of
+/// date, and link it into the browsable backup_root/date/YYYY/MM/DD
+/// tree.
+fn backup_file_content_addressed_layout(
+    cli: &CliArgs,
+    filename: &Path,
+    filename_name: &OsStr,
+    file_path: &str,
+    datetime: &DateTime<Utc>,
+    origin: PictureDatetimeOrigin
+) -> Result<BackupSuccess, BackupFailure> {
+    let hash = hash_file(filename)
+        .map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?
+        .to_string();
+    let shard_dir = Path::new(&cli.backup_root).join("content").join(&hash[..2]);
+    upsert_picture_directory(&shard_dir)
+        .map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?;
+
+    let extension = filename.extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let target_filename = shard_dir.join(format!("{}{}", hash, extension));
+    // The target path encodes the content hash, but its mere existence
+    // doesn't prove its content matches: a previous run could have been
+    // killed mid-copy and left a truncated file sitting at this exact
+    // path. Re-hash it before trusting it enough to remove the source.
+    let already_backed_up = target_filename.is_file()
+        && hash_file(&target_filename).map(|h| h.to_string() == hash).unwrap_or(false);
+    if !already_backed_up {
+        copy(filename, &target_filename)
+            .map_err(|_| BackupFailure::CopyError(String::from(file_path)))?;
+        let verified = hash_file(&target_filename).map(|h| h.to_string() == hash).unwrap_or(false);
+        maybe_remove_source(cli, file_path, verified);
+    } else {
+        maybe_remove_source(cli, file_path, true);
+    }
+
+    let date_dir = find_backup_dir(&Path::new(&cli.backup_root).join("date"), datetime);
+    upsert_picture_directory(&date_dir)
+        .map_err(|e| quarantine_file(&cli.backup_root, file_path, &e))?;
+    let date_link = date_dir.join(filename_name);
+    // Symlink targets are resolved relative to the link's own directory,
+    // not the current directory, so the link has to point at the content
+    // file via a path relative to `date_dir` rather than the (possibly
+    // relative-to-cwd) `target_filename`.
+    let symlink_target = date_link.parent()
+        .map(|dir| relative_to(&target_filename, dir))
+        .unwrap_or_else(|| target_filename.clone());
+
+    // `Path::exists` follows the link and would report a dangling
+    // symlink as absent, so `symlink()` would then fail with "file
+    // already exists"; check for the link entry itself instead.
+    if !date_link.is_symlink() {
+        if let Err(e) = std::os::unix::fs::symlink(&symlink_target, &date_link) {
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                return Err(BackupFailure::CopyError(format!("cannot create date symlink {}: {}", date_link.display(), e)));
+            }
+            // Lost a race with another worker thread (backups run on a
+            // rayon thread pool) linking the same name concurrently;
+            // fall back to the same check used when the link already
+            // existed up front.
+            warn_if_date_link_points_elsewhere(&date_link, &symlink_target, &target_filename, file_path);
+        }
+    } else {
+        warn_if_date_link_points_elsewhere(&date_link, &symlink_target, &target_filename, file_path);
+    }
+
+    if already_backed_up {
+        Ok(BackupSuccess::AlreadyBackup(String::from(file_path)))
+    } else {
+        Ok(BackupSuccess::Backup(target_filename, origin))
+    }
+}
+
+fn upsert_picture_directory(picture_dir: &PathBuf) -> Result<(), String> {
     // Prevent concurrent directory creation by locking a mutex.
     let _lock = CREATE_DIR_MUTEX.lock();
     if !picture_dir.exists() {
-            create_dir_all(picture_dir)
-            .unwrap_or_else(
-                |e| panic!("ERROR: cannot create the backup directory {}: {}", &picture_dir.display(), e)
-            );
+        create_dir_all(picture_dir)
+            .map_err(|e| format!("cannot create the backup directory {}: {}", picture_dir.display(), e))?;
     } else if !picture_dir.is_dir() {
-        panic!("ERROR: {} already exists and is not a directory. Can't use it to store a picture.", &picture_dir.display())
+        return Err(format!("{} already exists and is not a directory. Can't use it to store a picture.", picture_dir.display()));
+    }
+    Ok(())
+}
+
+/// Copy a picture we can't safely process into `backup_root/_quarantine`
+/// instead of crashing or filing it under a bogus filesystem-mtime
+/// date, and report it as a `BackupFailure::Corrupt`.
+fn quarantine_file(backup_root: &str, file_path: &str, reason: &str) -> BackupFailure {
+    let quarantine_dir = Path::new(backup_root).join("_quarantine");
+    if let Err(e) = upsert_picture_directory(&quarantine_dir) {
+        return BackupFailure::Corrupt(format!("{}: {} (and failed to prepare the quarantine dir: {})", file_path, reason, e));
+    }
+
+    match Path::new(file_path).file_name() {
+        Some(name) => {
+            let target = quarantine_dir.join(name);
+            match copy(file_path, &target) {
+                Ok(_) => BackupFailure::Corrupt(format!("{}: {} (quarantined to {})", file_path, reason, target.display())),
+                Err(e) => BackupFailure::Corrupt(format!("{}: {} (could not quarantine the file: {})", file_path, reason, e))
+            }
+        },
+        None => BackupFailure::Corrupt(format!("{}: {} (no file name, could not quarantine the file)", file_path, reason))
+    }
+}
+
+/// Probe that `file_path` is actually decodable, for the extensions the
+/// `image` crate supports, so a truncated or otherwise corrupt file
+/// with perfectly valid filesystem metadata gets quarantined instead of
+/// filed away untouched. Other extensions (RAW, HEIC, video) are
+/// skipped: `image` can't decode them either way, so there's nothing
+/// to validate here beyond the I/O-level checks already performed.
+/// Decoding untrusted images can panic inside C-backed libraries; that's
+/// caught here so a poisoned file never reaches a destructive step.
+fn validate_decodable(file_path: &str) -> Result<(), String> {
+    let is_decodable_image = Path::new(file_path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| DECODABLE_IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+    if !is_decodable_image {
+        return Ok(());
     }
+    let path = file_path.to_string();
+    std::panic::catch_unwind(|| {
+        image::open(&path).map(|_| ()).map_err(|e| format!("cannot decode {}: {}", path, e))
+    }).unwrap_or_else(|_| Err(format!("panicked while decoding {}", file_path)))
 }
 
 /// Retrieves when the picture has been shot from the EXIF metadata.
 /// If no datetime EXIF data is attached to the file, use the file
-/// last modification date.
-fn get_picture_datetime(file_path: &str, file: &File) -> (DateTime<Utc>, PictureDatetimeOrigin) {
+/// last modification date. Fails only if the file itself can't be
+/// read at all.
+fn get_picture_datetime(file_path: &str, file: &File) -> Result<(DateTime<Utc>, PictureDatetimeOrigin), String> {
     // Try exif crate.
-    get_picture_exif_datetime(file).map(|dt| (dt, PictureDatetimeOrigin::Exif))
-        // Exif failed, shell out to exiftool.
-        .or_else(|| {
-                 get_picture_exiftool_datetime(file_path)
-                .map(|dt| (dt, PictureDatetimeOrigin::ExifTool))})
-        // Exiftool failed as well. Fallback to Unix datetime.
-        .unwrap_or((get_file_modified_time(file_path, file), PictureDatetimeOrigin::FilesystemMetadata))
+    if let Some(dt) = get_picture_exif_datetime(file) {
+        return Ok((dt, PictureDatetimeOrigin::Exif));
+    }
+    // Exif failed, shell out to exiftool.
+    if let Some(dt) = get_picture_exiftool_datetime(file_path) {
+        return Ok((dt, PictureDatetimeOrigin::ExifTool));
+    }
+    // Exiftool failed as well. Fallback to Unix datetime.
+    get_file_modified_time(file_path, file).map(|dt| (dt, PictureDatetimeOrigin::FilesystemMetadata))
 }
 
 /// Retrieves the picture EXIF datetime.
@@ -183,7 +650,7 @@ fn get_picture_exif_datetime(file: &File) -> Option<DateTime<Utc>> {
     match datetime_field.value {
         Value::Ascii(ref vec) if !vec.is_empty() => {
             // Meh… I know…
-            let str_date = String::from_utf8(vec[0].to_vec()).unwrap();
+            let str_date = String::from_utf8(vec[0].to_vec()).ok()?;
             NaiveDateTime::parse_from_str(&str_date, "%Y:%m:%d %H:%M:%S")
                 .map(|naive_datetime| DateTime::from_utc(naive_datetime, Utc))
                 .ok()
@@ -217,30 +684,54 @@ fn get_picture_exiftool_datetime(file_path: &str) -> Option<DateTime<Utc>> {
 
 /// If we cannot load the EXIF creation datetime, we end up using the
 /// last modified time of the file.
-fn get_file_modified_time(file_path: &str, file: &File) -> DateTime<Utc> {
+fn get_file_modified_time(file_path: &str, file: &File) -> Result<DateTime<Utc>, String> {
     let systemtime = file.metadata()
-        .unwrap_or_else(|_| panic!("Cannot retrieve UNIX file metadata for {}", file_path))
+        .map_err(|e| format!("cannot retrieve UNIX file metadata for {}: {}", file_path, e))?
         .modified()
-        .unwrap_or_else(|_| panic!("Cannot retrieve modified time for {}", file_path));
-    systemtime.into()
+        .map_err(|e| format!("cannot retrieve modified time for {}: {}", file_path, e))?;
+    Ok(systemtime.into())
 }
 
 /// Return directory in which we want to save the picture.
-fn find_backup_dir(backup_root: &str, datetime: &DateTime<Utc>) -> PathBuf {
-    let backup_root = Path::new(backup_root);
+fn find_backup_dir(backup_root: &Path, datetime: &DateTime<Utc>) -> PathBuf {
     backup_root
         .join(format!("{:04}", datetime.year()))
         .join(format!("{:02}", datetime.month()))
         .join(format!("{:02}", datetime.day()))
 }
 
+/// Compute `target` as a path relative to `from`, falling back to
+/// `target` itself when no relative path can be derived between them
+/// (e.g. paths on different Windows drives), so a fallback symlink or
+/// link is still better than a missing one.
+fn relative_to(target: &Path, from: &Path) -> PathBuf {
+    diff_paths(target, from).unwrap_or_else(|| target.to_path_buf())
+}
+
+/// Warn if `date_link` doesn't already point at `symlink_target`,
+/// instead of silently dropping a picture that lost a naming collision
+/// against whatever claimed that date-tree link name first (two
+/// distinct pictures sharing a filename and a backup day, or two rayon
+/// worker threads racing to create the same link).
+fn warn_if_date_link_points_elsewhere(date_link: &Path, symlink_target: &Path, target_filename: &Path, file_path: &str) {
+    match std::fs::read_link(date_link) {
+        Ok(ref existing) if existing == symlink_target => (),
+        Ok(_) => eprintln!(
+            "WARNING: {} already links to a different picture; not relinking it for {} (still stored at {})",
+            date_link.display(), file_path, target_filename.display()
+        ),
+        Err(e) => eprintln!("WARNING: cannot read existing date symlink {}: {}", date_link.display(), e)
+    }
+}
+
 /// Sanity function making sure the user did not give us complete
 /// garbage data.
 fn validate_args(args: &CliArgs) {
     match &args.file_path {
         Some(file_path) => {
-            if !Path::new(&file_path).is_file() {
-                panic!("ERROR: {} is not a file", &file_path);
+            let path = Path::new(&file_path);
+            if !path.is_file() && !path.is_dir() {
+                panic!("ERROR: {} is not a file or a directory", &file_path);
             };
         }
         None => ()
@@ -259,20 +750,501 @@ fn validate_args(args: &CliArgs) {
     if !exif_tool_in_path {
         eprintln!("Exiftool doesn't seem to be present in $PATH. Install it if you want to be able to extract more pictures metadata");
     }
+
+    if args.write_dates && args.layout == Layout::ContentAddressed {
+        eprintln!("WARNING: --write-dates is ignored with --layout content-addressed: stamping dates would change the file's content after its path was already assigned from its hash");
+    }
 }
 
-/// Compare two files and check if they're the same. We're not really
-/// comparing the whole file, it'd be too expensive. We assume that if
-/// two pictures have the same EXIF data, the same size and the same
-/// creation date, they're the same.
-fn same_files(source: &Path, target: &Path) -> bool {
-    let source_file = File::open(source)
-        .unwrap_or_else(|_| panic!("Error: cannot open file {}", &source.display()))
+/// Compare two files and check if they're the same. We first compare
+/// the file sizes as a cheap short-circuit, then fall back to a real
+/// content digest (BLAKE3) streamed over both files in fixed-size
+/// chunks, so we never have to hold a whole picture in memory to know
+/// whether it's a duplicate.
+fn same_files(source: &Path, target: &Path) -> Result<bool, String> {
+    let source_len = File::open(source)
+        .map_err(|e| format!("cannot open file {}: {}", source.display(), e))?
         .metadata()
-        .unwrap_or_else(|_| panic!("Error: cannot get metadata of  file {}", &source.display()));
-    let target_file = File::open(target)
-        .unwrap_or_else(|_| panic!("Error: cannot open file {}", &target.display()))
+        .map_err(|e| format!("cannot get metadata of file {}: {}", source.display(), e))?
+        .len();
+    let target_len = File::open(target)
+        .map_err(|e| format!("cannot open file {}: {}", target.display(), e))?
         .metadata()
-        .unwrap_or_else(|_| panic!("Error: cannot get metadata of  file {}", &target.display()));
-    source_file.len() == target_file.len()
+        .map_err(|e| format!("cannot get metadata of file {}: {}", target.display(), e))?
+        .len();
+
+    if source_len != target_len {
+        return Ok(false);
+    }
+    Ok(hash_file(source)? == hash_file(target)?)
+}
+
+/// Compute the BLAKE3 digest of a file, streaming it in fixed-size
+/// chunks so large pictures never need to be fully loaded in memory.
+fn hash_file(path: &Path) -> Result<blake3::Hash, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open file {}: {}", path.display(), e))?;
+    let mut reader = std::io::BufReader::new(file);
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = [0u8; HASH_CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buf)
+            .map_err(|e| format!("cannot read file {}: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cli_args(backup_root: &str, move_files: bool) -> CliArgs {
+        test_cli_args_with(backup_root, Layout::Date, move_files)
+    }
+
+    fn test_cli_args_with(backup_root: &str, layout: Layout, move_files: bool) -> CliArgs {
+        CliArgs {
+            backup_root: backup_root.to_string(),
+            file_path: None,
+            layout,
+            write_dates: false,
+            one_file_system: false,
+            gallery: false,
+            thumb_size: 1600,
+            move_files,
+        }
+    }
+
+    fn test_datetime() -> DateTime<Utc> {
+        let naive = NaiveDateTime::parse_from_str("2024:01:15 10:00:00", "%Y:%m:%d %H:%M:%S").unwrap();
+        DateTime::from_utc(naive, Utc)
+    }
+
+    #[test]
+    fn content_addressed_layout_creates_content_file_and_date_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source = dir.path().join("photo.jpg");
+        std::fs::write(&source, b"some photo bytes").unwrap();
+        let cli = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, false);
+        let datetime = test_datetime();
+
+        let result = backup_file_content_addressed_layout(
+            &cli, &source, OsStr::new("photo.jpg"), source.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        let target = match result {
+            Ok(BackupSuccess::Backup(target, _)) => target,
+            Ok(BackupSuccess::AlreadyBackup(_)) => panic!("expected a fresh backup, not a duplicate"),
+            Err(_) => panic!("expected the backup to succeed")
+        };
+
+        let hash = hash_file(&source).unwrap().to_string();
+        assert!(target.to_string_lossy().contains(&hash));
+        assert!(target.is_file());
+
+        let date_link = backup_root.join("date").join("2024").join("01").join("15").join("photo.jpg");
+        assert_eq!(
+            std::fs::canonicalize(&date_link).unwrap(),
+            std::fs::canonicalize(Path::new(&target)).unwrap()
+        );
+    }
+
+    #[test]
+    fn content_addressed_layout_dedups_identical_content_and_removes_source_under_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source_a = dir.path().join("a.jpg");
+        let source_b = dir.path().join("b.jpg");
+        std::fs::write(&source_a, b"identical bytes").unwrap();
+        std::fs::write(&source_b, b"identical bytes").unwrap();
+        let datetime = test_datetime();
+
+        let cli_copy = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, false);
+        if backup_file_content_addressed_layout(
+            &cli_copy, &source_a, OsStr::new("a.jpg"), source_a.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        ).is_err() {
+            panic!("expected the first backup to succeed");
+        }
+
+        let cli_move = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, true);
+        let result = backup_file_content_addressed_layout(
+            &cli_move, &source_b, OsStr::new("b.jpg"), source_b.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        match result {
+            Ok(BackupSuccess::AlreadyBackup(_)) => (),
+            Ok(BackupSuccess::Backup(..)) => panic!("expected the duplicate to be recognized as already backed up"),
+            Err(_) => panic!("expected the duplicate import to succeed")
+        }
+        assert!(!source_b.exists(), "a verified duplicate's source should be removed under --move");
+    }
+
+    #[test]
+    fn content_addressed_layout_repairs_a_truncated_pre_existing_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source = dir.path().join("photo.jpg");
+        std::fs::write(&source, b"the real photo bytes").unwrap();
+        let hash = hash_file(&source).unwrap().to_string();
+
+        // Simulate a previous run that was killed mid-copy, leaving a
+        // truncated file sitting at the exact path its content hash
+        // would claim.
+        let shard_dir = backup_root.join("content").join(&hash[..2]);
+        std::fs::create_dir_all(&shard_dir).unwrap();
+        let target_filename = shard_dir.join(format!("{}.jpg", hash));
+        std::fs::write(&target_filename, b"truncat").unwrap();
+
+        let cli = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, true);
+        let datetime = test_datetime();
+        let result = backup_file_content_addressed_layout(
+            &cli, &source, OsStr::new("photo.jpg"), source.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        match result {
+            Ok(BackupSuccess::Backup(..)) => (),
+            Ok(BackupSuccess::AlreadyBackup(_)) => panic!("a truncated target must not be trusted as already backed up"),
+            Err(_) => panic!("expected the repair copy to succeed")
+        }
+        assert_eq!(hash_file(&target_filename).unwrap().to_string(), hash);
+        assert!(!source.exists(), "the repaired, re-verified copy should allow source removal under --move");
+    }
+
+    #[test]
+    fn content_addressed_layout_warns_instead_of_dropping_a_date_link_name_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source_a = dir.path().join("a.jpg");
+        let source_b = dir.path().join("b.jpg");
+        std::fs::write(&source_a, b"camera one bytes").unwrap();
+        std::fs::write(&source_b, b"camera two bytes").unwrap();
+        let datetime = test_datetime();
+        let cli = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, false);
+
+        // Both pictures are filed under the same original filename on
+        // the same day, as if they came off two different cameras.
+        let first = backup_file_content_addressed_layout(
+            &cli, &source_a, OsStr::new("img.jpg"), source_a.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        let first_target = match first {
+            Ok(BackupSuccess::Backup(target, _)) => target,
+            _ => panic!("expected the first backup to succeed")
+        };
+        let second = backup_file_content_addressed_layout(
+            &cli, &source_b, OsStr::new("img.jpg"), source_b.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        let second_target = match second {
+            Ok(BackupSuccess::Backup(target, _)) => target,
+            _ => panic!("expected the second backup to still succeed and be stored under content/")
+        };
+
+        assert_ne!(first_target, second_target);
+        assert!(Path::new(&second_target).is_file());
+
+        // The date-tree link must still point at whichever picture
+        // claimed the name first, not silently vanish or get corrupted.
+        let date_link = backup_root.join("date").join("2024").join("01").join("15").join("img.jpg");
+        assert_eq!(
+            std::fs::canonicalize(&date_link).unwrap(),
+            std::fs::canonicalize(Path::new(&first_target)).unwrap()
+        );
+    }
+
+    #[test]
+    fn content_addressed_layout_repairs_a_dangling_date_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source = dir.path().join("photo.jpg");
+        std::fs::write(&source, b"some photo bytes").unwrap();
+        let cli = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, false);
+        let datetime = test_datetime();
+
+        let first = backup_file_content_addressed_layout(
+            &cli, &source, OsStr::new("photo.jpg"), source.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        let first_target = match first {
+            Ok(BackupSuccess::Backup(target, _)) => target,
+            _ => panic!("expected the first backup to succeed")
+        };
+
+        // Simulate the content file having been removed out-of-band
+        // (manual cleanup, a botched migration, ...), leaving the
+        // date-tree entry dangling even though nothing else changed.
+        std::fs::remove_file(&first_target).unwrap();
+        let date_link = backup_root.join("date").join("2024").join("01").join("15").join("photo.jpg");
+        assert!(date_link.is_symlink());
+        assert!(std::fs::canonicalize(&date_link).is_err(), "the link should be dangling for this test to be meaningful");
+
+        let second = backup_file_content_addressed_layout(
+            &cli, &source, OsStr::new("photo.jpg"), source.to_str().unwrap(), &datetime, PictureDatetimeOrigin::FilesystemMetadata
+        );
+        match second {
+            Ok(_) => (),
+            Err(_) => panic!("expected re-processing the same source to repair the dangling link, not fail")
+        }
+
+        assert!(Path::new(&first_target).is_file(), "the content file should have been recreated");
+        assert_eq!(
+            std::fs::canonicalize(&date_link).unwrap(),
+            std::fs::canonicalize(Path::new(&first_target)).unwrap()
+        );
+    }
+
+    #[test]
+    fn backup_file_skips_write_dates_under_content_addressed_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        // .cr2 is outside DECODABLE_IMAGE_EXTENSIONS, so validate_decodable
+        // skips it and arbitrary bytes are enough to exercise this path.
+        let source = dir.path().join("photo.cr2");
+        std::fs::write(&source, b"not a real raw photo, just bytes").unwrap();
+        let hash_before = hash_file(&source).unwrap();
+
+        let mut cli = test_cli_args_with(backup_root.to_str().unwrap(), Layout::ContentAddressed, false);
+        cli.write_dates = true;
+
+        let result = backup_file(&cli, source.to_str().unwrap());
+        let target = match result {
+            Ok(BackupSuccess::Backup(target, _)) => target,
+            Ok(BackupSuccess::AlreadyBackup(_)) => panic!("expected a fresh backup"),
+            Err(_) => panic!("expected the backup to succeed")
+        };
+
+        // Content-addressed paths are derived from a pre-write hash;
+        // stamping dates into the file afterwards would silently break
+        // that invariant, so --write-dates must be a no-op here.
+        assert_eq!(hash_file(Path::new(&target)).unwrap(), hash_before);
+    }
+
+    #[test]
+    fn quarantine_file_copies_into_quarantine_dir_and_reports_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source = dir.path().join("broken.jpg");
+        std::fs::write(&source, b"not a real jpeg").unwrap();
+
+        let failure = quarantine_file(backup_root.to_str().unwrap(), source.to_str().unwrap(), "cannot decode");
+
+        match failure {
+            BackupFailure::Corrupt(msg) => assert!(msg.contains("cannot decode")),
+            _ => panic!("expected a Corrupt failure")
+        }
+        assert!(backup_root.join("_quarantine").join("broken.jpg").is_file());
+    }
+
+    #[test]
+    fn validate_decodable_skips_non_decodable_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.cr2");
+        std::fs::write(&path, b"definitely not a real raw file").unwrap();
+        assert_eq!(validate_decodable(path.to_str().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn validate_decodable_rejects_a_corrupt_decodable_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, b"definitely not a real jpeg").unwrap();
+        assert!(validate_decodable(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn validate_decodable_accepts_a_real_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.png");
+        image::RgbImage::new(4, 4).save(&path).unwrap();
+        assert_eq!(validate_decodable(path.to_str().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn backup_file_quarantines_a_corrupt_decodable_image_instead_of_panicking() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("backup");
+        std::fs::create_dir_all(&backup_root).unwrap();
+        let source = dir.path().join("broken.jpg");
+        std::fs::write(&source, b"not a real jpeg").unwrap();
+        let cli = test_cli_args(backup_root.to_str().unwrap(), false);
+
+        let result = backup_file(&cli, source.to_str().unwrap());
+
+        match result {
+            Err(BackupFailure::Corrupt(_)) => (),
+            _ => panic!("expected the corrupt file to be quarantined")
+        }
+        assert!(backup_root.join("_quarantine").join("broken.jpg").is_file());
+    }
+
+    #[test]
+    fn collect_directory_file_paths_recurses_and_filters_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.jpg"), b"x").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"x").unwrap();
+        std::fs::write(dir.path().join("sub").join("b.PNG"), b"x").unwrap();
+        std::fs::write(dir.path().join("sub").join(".DS_Store"), b"x").unwrap();
+
+        let mut found: Vec<String> = collect_directory_file_paths(dir.path(), false)
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|_| panic!("expected every entry to be Ok")))
+            .collect();
+        found.sort();
+
+        let mut expected = vec![
+            dir.path().join("a.jpg").to_string_lossy().into_owned(),
+            dir.path().join("sub").join("b.PNG").to_string_lossy().into_owned(),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn collect_directory_file_paths_keeps_everything_on_a_single_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("a.jpg"), b"x").unwrap();
+
+        // The whole tempdir lives on one filesystem, so --one-file-system
+        // pruning must be a no-op here; it only prunes at a device-id
+        // boundary.
+        let found = collect_directory_file_paths(dir.path(), true);
+        assert_eq!(found.len(), 1);
+    }
+
+    #[test]
+    fn is_media_file_matches_extensions_case_insensitively() {
+        assert!(is_media_file(Path::new("photo.JPG")));
+        assert!(is_media_file(Path::new("clip.MOV")));
+        assert!(!is_media_file(Path::new("notes.txt")));
+        assert!(!is_media_file(Path::new("noextension")));
+    }
+
+    #[test]
+    fn generate_thumbnail_resizes_and_writes_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.png");
+        image::RgbImage::new(100, 50).save(&source).unwrap();
+        let target = dir.path().join("thumb.png");
+
+        generate_thumbnail(&source, &target, 20).unwrap();
+
+        let thumb = image::open(&target).unwrap();
+        assert!(thumb.width() <= 20 && thumb.height() <= 20);
+    }
+
+    #[test]
+    fn html_escape_escapes_attribute_breaking_characters() {
+        assert_eq!(html_escape("a \"quote\" & <tag>"), "a &quot;quote&quot; &amp; &lt;tag&gt;");
+    }
+
+    #[test]
+    fn render_gallery_index_html_escapes_filenames_and_uses_relative_paths() {
+        let day_dir = Path::new("/backup/.thumbs/2024/01/15");
+        let entries = vec![
+            GalleryEntry {
+                original: Path::new("/backup/date/2024/01/15/\"evil\".jpg").to_path_buf(),
+                thumbnail: day_dir.join("\"evil\".jpg"),
+                datetime: test_datetime(),
+            }
+        ];
+
+        let html = render_gallery_index_html((2024, 1, 15), &entries, day_dir);
+
+        assert!(!html.contains("\"evil\".jpg\""), "the raw unescaped filename must not appear in an attribute");
+        assert!(html.contains("href=\"../../../../date/2024/01/15/&quot;evil&quot;.jpg\""));
+        assert!(html.contains("src=\"&quot;evil&quot;.jpg\""));
+    }
+
+    #[test]
+    fn maybe_remove_source_removes_verified_copy_under_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::write(&source, b"hello world").unwrap();
+        let cli = test_cli_args(dir.path().to_str().unwrap(), true);
+
+        maybe_remove_source(&cli, source.to_str().unwrap(), true);
+
+        assert!(!source.exists());
+    }
+
+    #[test]
+    fn maybe_remove_source_keeps_unverified_copy_under_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::write(&source, b"hello world").unwrap();
+        let cli = test_cli_args(dir.path().to_str().unwrap(), true);
+
+        maybe_remove_source(&cli, source.to_str().unwrap(), false);
+
+        assert!(source.exists());
+    }
+
+    #[test]
+    fn maybe_remove_source_is_a_no_op_without_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::write(&source, b"hello world").unwrap();
+        let cli = test_cli_args(dir.path().to_str().unwrap(), false);
+
+        maybe_remove_source(&cli, source.to_str().unwrap(), true);
+
+        assert!(source.exists());
+    }
+
+    #[test]
+    fn same_files_detects_identical_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, b"hello world").unwrap();
+        std::fs::write(&b, b"hello world").unwrap();
+        assert_eq!(same_files(&a, &b), Ok(true));
+    }
+
+    #[test]
+    fn same_files_detects_different_content_same_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, b"hello world").unwrap();
+        std::fs::write(&b, b"hellO world").unwrap();
+        assert_eq!(same_files(&a, &b), Ok(false));
+    }
+
+    #[test]
+    fn same_files_short_circuits_on_different_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, b"hello world").unwrap();
+        std::fs::write(&b, b"hello world, much longer").unwrap();
+        assert_eq!(same_files(&a, &b), Ok(false));
+    }
+
+    #[test]
+    fn hash_file_is_stable_and_content_dependent() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, b"hello world").unwrap();
+        std::fs::write(&b, b"goodbye world").unwrap();
+        assert_eq!(hash_file(&a).unwrap(), hash_file(&a).unwrap());
+        assert_ne!(hash_file(&a).unwrap(), hash_file(&b).unwrap());
+    }
+
+    #[test]
+    fn hash_file_streams_across_chunk_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big");
+        let content = vec![42u8; HASH_CHUNK_SIZE * 3 + 17];
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(hash_file(&path).unwrap(), blake3::hash(&content));
+    }
 }